@@ -0,0 +1,70 @@
+/*
+ * This file is part of libespm
+ *
+ * Copyright (C) 2017 Oliver Hamlet
+ *
+ * libespm is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * libespm is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with libespm. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use serde::{Deserialize, Serialize};
+
+/// A record's identifier, resolved to the plugin that owns it and an object
+/// index local to that plugin.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct FormId {
+    plugin_name: String,
+    object_index: u32,
+}
+
+impl FormId {
+    /// Resolves a record's raw, file-local form ID into the plugin that
+    /// owns it and the object index within that plugin.
+    ///
+    /// The top byte of `raw_form_id` indexes into `masters`: a value less
+    /// than `masters.len()` means the record is an override of that master,
+    /// while any other value means `plugin_name` itself defines the record.
+    /// When `plugin_is_light` is true, `plugin_name`'s own records only
+    /// occupy a light master's compacted 12-bit object-index space, so the
+    /// low 3 bytes of `raw_form_id` are masked down to that range rather
+    /// than kept in full.
+    pub fn new(plugin_name: &str, masters: &[String], raw_form_id: u32, plugin_is_light: bool) -> FormId {
+        let master_index = (raw_form_id >> 24) as usize;
+
+        if master_index < masters.len() {
+            FormId {
+                plugin_name: masters[master_index].clone(),
+                object_index: raw_form_id & 0x00FF_FFFF,
+            }
+        } else {
+            let object_index = if plugin_is_light {
+                raw_form_id & 0x0000_0FFF
+            } else {
+                raw_form_id & 0x00FF_FFFF
+            };
+
+            FormId {
+                plugin_name: plugin_name.to_string(),
+                object_index: object_index,
+            }
+        }
+    }
+
+    pub fn plugin_name(&self) -> &str {
+        &self.plugin_name
+    }
+
+    pub fn object_index(&self) -> u32 {
+        self.object_index
+    }
+}
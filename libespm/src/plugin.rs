@@ -23,13 +23,17 @@ use std::fs::File;
 use std::io;
 use std::io::BufReader;
 use std::io::Read;
+use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 use std::str;
+use std::time::SystemTime;
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use bincode;
 
-use encoding::{Encoding, DecoderTrap};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use encoding::{Encoding, DecoderTrap, EncoderTrap};
 use encoding::all::WINDOWS_1252;
 
 use nom::ErrorKind;
@@ -39,10 +43,55 @@ use nom::IResult;
 use memmap::Mmap;
 use memmap::Protection;
 
+use serde::{Deserialize, Serialize};
+
 use form_id::FormId;
 use game_id::GameId;
 use group::Group;
 use record::Record;
+use subrecord::Subrecord;
+
+/// The size in bytes of a TES4/TES5/FO3/FO4 record header: type, data size,
+/// flags, form ID, version control info and an internal version/unknown
+/// field, each 4 bytes.
+const RECORD_HEADER_SIZE: usize = 24;
+
+/// The size in bytes of a TES3 record header: type, data size, an unknown
+/// field and flags, each 4 bytes.
+const TES3_RECORD_HEADER_SIZE: usize = 16;
+
+/// The header flag marking a plugin as a light master (ESL).
+const LIGHT_MASTER_FLAG: u32 = 0x200;
+
+/// The range of object indices a light master's records may legally occupy,
+/// as Bethesda's tools compact them into a 12-bit index when resolving a
+/// plugin's global FormIDs in a load order.
+const LIGHT_MASTER_MIN_OBJECT_INDEX: u32 = 0x800;
+const LIGHT_MASTER_MAX_OBJECT_INDEX: u32 = 0xFFF;
+
+fn is_light_flagged(game_id: GameId, header_flags: u32, filename: &str) -> bool {
+    if game_id == GameId::Morrowind {
+        return false;
+    }
+
+    if header_flags & LIGHT_MASTER_FLAG != 0 {
+        return true;
+    }
+
+    // A ghosted plugin's real extension is hidden behind a `.ghost` suffix,
+    // e.g. "Plugin.esl.ghost", so that has to be stripped before checking
+    // for "esl".
+    let path = Path::new(filename);
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) if extension.eq_ignore_ascii_case("esl") => true,
+        Some(extension) if extension.eq_ignore_ascii_case("ghost") => path
+            .file_stem()
+            .and_then(|stem| Path::new(stem).extension())
+            .and_then(|extension| extension.to_str())
+            .map_or(false, |extension| extension.eq_ignore_ascii_case("esl")),
+        _ => false,
+    }
+}
 
 #[derive(Debug)]
 pub enum Error {
@@ -53,6 +102,8 @@ pub enum Error {
     ParsingIncomplete,
     ParsingError,
     DecodeError(Cow<'static, str>),
+    CacheError(Box<bincode::ErrorKind>),
+    PluginNotFullyParsed,
 }
 
 impl From<IError> for Error {
@@ -76,10 +127,39 @@ impl From<Cow<'static, str>> for Error {
     }
 }
 
-#[derive(Clone, Debug, Default)]
+impl From<Box<bincode::ErrorKind>> for Error {
+    fn from(error: Box<bincode::ErrorKind>) -> Self {
+        Error::CacheError(error)
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 struct PluginData {
     header_record: Record,
     form_ids: Vec<FormId>,
+
+    /// FormIDs of records this plugin itself defines whose object index
+    /// falls outside the range a light master's compacted index can
+    /// represent, i.e. ones that would be silently corrupted by flagging
+    /// this plugin as ESL. Always empty unless the plugin is light.
+    invalid_light_form_ids: Vec<u32>,
+
+    /// The exact bytes the header record was parsed from, kept so that
+    /// `Plugin::write_file` can copy the fixed fields it doesn't model
+    /// (form ID, version control info, etc.) verbatim.
+    header_bytes: Vec<u8>,
+
+    /// The plugin's content following the header record, kept verbatim so
+    /// `Plugin::write_file` can round-trip a fully parsed plugin without
+    /// re-encoding every record. Empty whenever `header_only` is `true`.
+    body: Vec<u8>,
+
+    /// Whether this data came from a header-only parse (`parse_file(true)`
+    /// or `parse_file_header`) rather than a full one. A header-only parse
+    /// has no `body`, so anything that needs to round-trip the whole
+    /// plugin (`Plugin::write_file`, a full-parse cache lookup) must check
+    /// this first.
+    header_only: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -89,6 +169,16 @@ pub struct Plugin {
     data: PluginData,
 }
 
+/// The parsed metadata for a plugin, bundled with the source file's size and
+/// modification time so a cache on disk can be checked for staleness without
+/// re-parsing the plugin it was built from.
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    file_size: u64,
+    modified: SystemTime,
+    data: PluginData,
+}
+
 impl Plugin {
     pub fn new(game_id: GameId, filepath: &Path) -> Plugin {
         Plugin {
@@ -130,6 +220,104 @@ impl Plugin {
         self.parse(mmap_slice, load_header_only)
     }
 
+    /// Parses only the plugin's header record, reading just the bytes that
+    /// record occupies rather than the whole file. This is much cheaper than
+    /// `parse_file(true)` for large masters, as it bounds I/O and peak
+    /// memory to the size of the header record instead of the whole plugin.
+    pub fn parse_file_header(&mut self) -> Result<(), Error> {
+        let filename = self.filename().ok_or(Error::NoFilename)?;
+
+        let f = File::open(self.path.clone())?;
+        let file_len = f.metadata()?.len();
+        let mut reader = BufReader::new(f);
+
+        let header_size = if self.game_id == GameId::Morrowind {
+            TES3_RECORD_HEADER_SIZE
+        } else {
+            RECORD_HEADER_SIZE
+        };
+
+        let mut record_bytes = vec![0u8; header_size];
+        reader.read_exact(&mut record_bytes)?;
+
+        let data_size = Cursor::new(&record_bytes[4..8]).read_u32::<LittleEndian>()? as usize;
+
+        let remaining_len = file_len.saturating_sub(header_size as u64);
+        if data_size as u64 > remaining_len {
+            return Err(Error::ParsingError);
+        }
+
+        let header_len = record_bytes.len();
+        record_bytes.resize(header_len + data_size, 0);
+        reader.read_exact(&mut record_bytes[header_len..])?;
+
+        self.data = parse_plugin(&record_bytes, self.game_id, &filename, true).to_full_result()?;
+
+        Ok(())
+    }
+
+    /// Parses the plugin using `cache_path` if it holds a cache written for
+    /// the current size and modification time of the plugin's file, falling
+    /// back to `parse_file(load_header_only)` and rewriting the cache
+    /// otherwise. A cache written from a header-only parse is not reused
+    /// when `load_header_only` is `false`.
+    pub fn parse_file_use_cache(
+        &mut self,
+        load_header_only: bool,
+        cache_path: &Path,
+    ) -> Result<(), Error> {
+        let metadata = self.path.metadata()?;
+        let file_size = metadata.len();
+        let modified = metadata.modified()?;
+
+        if let Ok(entry) = Self::read_cache_entry(cache_path) {
+            let stamps_match = entry.file_size == file_size && entry.modified == modified;
+            let satisfies_request = load_header_only || !entry.data.header_only;
+
+            if stamps_match && satisfies_request {
+                self.data = entry.data;
+                return Ok(());
+            }
+        }
+
+        self.parse_file(load_header_only)?;
+        self.write_cache(cache_path)?;
+
+        Ok(())
+    }
+
+    /// Writes this plugin's parsed metadata to `cache_path`, keyed on the
+    /// source file's current size and modification time.
+    pub fn write_cache(&self, cache_path: &Path) -> Result<(), Error> {
+        let metadata = self.path.metadata()?;
+
+        let entry = CacheEntry {
+            file_size: metadata.len(),
+            modified: metadata.modified()?,
+            data: self.data.clone(),
+        };
+
+        let mut file = File::create(cache_path)?;
+        file.write_all(&bincode::serialize(&entry)?)?;
+
+        Ok(())
+    }
+
+    /// Loads parsed metadata previously written by `write_cache`, without
+    /// checking it against the source file's current size or modification
+    /// time.
+    pub fn load_cache(&mut self, cache_path: &Path) -> Result<(), Error> {
+        self.data = Self::read_cache_entry(cache_path)?.data;
+
+        Ok(())
+    }
+
+    fn read_cache_entry(cache_path: &Path) -> Result<CacheEntry, Error> {
+        let file = File::open(cache_path)?;
+
+        Ok(bincode::deserialize_from(BufReader::new(file))?)
+    }
+
     pub fn game_id(&self) -> &GameId {
         &self.game_id
     }
@@ -150,7 +338,9 @@ impl Plugin {
     }
 
     pub fn is_master_file(&self) -> bool {
-        if self.game_id != GameId::Morrowind {
+        if self.is_light_plugin() {
+            true
+        } else if self.game_id != GameId::Morrowind {
             self.data.header_record.header.flags & 0x1 != 0
         } else {
             match self.path.extension() {
@@ -168,10 +358,34 @@ impl Plugin {
         }
     }
 
+    /// Whether this plugin is a light master (ESL), as indicated by its
+    /// header flags or, failing that, its file extension. Light masters'
+    /// records are loaded into a compacted object-index range, so their
+    /// FormIDs must be interpreted differently (see `overlap_form_ids`).
+    pub fn is_light_plugin(&self) -> bool {
+        let filename = self.filename().unwrap_or_default();
+
+        is_light_flagged(self.game_id, self.data.header_record.header.flags, &filename)
+    }
+
+    /// The FormIDs of records this plugin itself defines that fall outside
+    /// the object-index range a light master can represent. A non-empty
+    /// result means the plugin cannot legally be flagged as ESL without
+    /// corrupting those records' FormIDs.
+    pub fn overlap_form_ids(&self) -> &[u32] {
+        &self.data.invalid_light_form_ids
+    }
+
     pub fn is_valid(game_id: GameId, filepath: &Path, load_header_only: bool) -> bool {
         let mut plugin = Plugin::new(game_id, &filepath.to_path_buf());
 
-        match plugin.parse_file(load_header_only) {
+        let result = if load_header_only {
+            plugin.parse_file_header()
+        } else {
+            plugin.parse_file(load_header_only)
+        };
+
+        match result {
             Ok(_) => true,
             Err(_) => false,
         }
@@ -219,6 +433,154 @@ impl Plugin {
     pub fn form_ids(&self) -> &Vec<FormId> {
         &self.data.form_ids
     }
+
+    /// Replaces this plugin's list of masters, re-encoding each as a
+    /// NUL-terminated WINDOWS-1252 `MAST` subrecord in place of the existing
+    /// ones.
+    pub fn set_masters(&mut self, masters: &[String]) -> Result<(), Error> {
+        let new_subrecords = masters
+            .iter()
+            .map(|master| encode_subrecord("MAST", master))
+            .collect::<Result<Vec<Subrecord>, Error>>()?;
+
+        let subrecords = &mut self.data.header_record.subrecords;
+        let insert_at = subrecords
+            .iter()
+            .position(|s| s.subrecord_type == "MAST")
+            .unwrap_or_else(|| subrecords.len());
+
+        subrecords.retain(|s| s.subrecord_type != "MAST");
+
+        for (offset, subrecord) in new_subrecords.into_iter().enumerate() {
+            subrecords.insert(insert_at + offset, subrecord);
+        }
+
+        Ok(())
+    }
+
+    /// Replaces this plugin's description, re-encoding it as WINDOWS-1252.
+    /// For Morrowind plugins the description is a fixed-size field within
+    /// the `HEDR` subrecord, so `description` must fit within the space the
+    /// source plugin reserved for it.
+    pub fn set_description(&mut self, description: &str) -> Result<(), Error> {
+        let encoded = WINDOWS_1252
+            .encode(description, EncoderTrap::Strict)
+            .map_err(Error::DecodeError)?;
+
+        if self.game_id == GameId::Morrowind {
+            let subrecord = self.data
+                .header_record
+                .subrecords
+                .iter_mut()
+                .find(|s| s.subrecord_type == "HEDR")
+                .ok_or(Error::ParsingError)?;
+
+            // The description field is 256 bytes at offset 40, directly
+            // followed by the record count read by `record_and_group_count`.
+            if subrecord.data.len() < 296 {
+                return Err(Error::ParsingError);
+            }
+
+            let slot_len = 256 - 1;
+            if encoded.len() > slot_len {
+                return Err(Error::DecodeError(Cow::from(
+                    "description is too long for this plugin's HEDR field",
+                )));
+            }
+
+            for byte in &mut subrecord.data[40..296] {
+                *byte = 0;
+            }
+            subrecord.data[40..(40 + encoded.len())].copy_from_slice(&encoded);
+        } else {
+            let mut data = encoded;
+            data.push(0);
+
+            match self.data
+                .header_record
+                .subrecords
+                .iter_mut()
+                .find(|s| s.subrecord_type == "SNAM")
+            {
+                Some(subrecord) => subrecord.data = data,
+                None => self.data.header_record.subrecords.push(Subrecord {
+                    subrecord_type: "SNAM".to_string(),
+                    data: data,
+                }),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Re-serializes this plugin's header record and writes the result to
+    /// `path`, followed by the rest of the plugin's content verbatim. The
+    /// header record's fixed fields (form ID, version control info, etc.)
+    /// are copied from the bytes it was parsed from; only its data size is
+    /// recomputed, since editing masters or the description changes how
+    /// many bytes its subrecords occupy. Subrecord sizes are written as a
+    /// 4-byte field for Morrowind and a 2-byte field for every other game,
+    /// matching how each is read.
+    ///
+    /// Returns `Error::PluginNotFullyParsed` if this plugin was parsed
+    /// header-only (`parse_file(true)` or `parse_file_header`), since its
+    /// body was never read and writing it out would silently drop the rest
+    /// of the plugin's content. Returns `Error::DecodeError` if a non-
+    /// Morrowind subrecord's data is too large for its 2-byte size field.
+    pub fn write_file(&self, path: &Path) -> Result<(), Error> {
+        if self.data.header_only {
+            return Err(Error::PluginNotFullyParsed);
+        }
+
+        let header_len = if self.game_id == GameId::Morrowind {
+            TES3_RECORD_HEADER_SIZE
+        } else {
+            RECORD_HEADER_SIZE
+        };
+        let fixed_prefix = &self.data.header_bytes[0..header_len];
+
+        let mut subrecord_bytes = Vec::new();
+        for subrecord in &self.data.header_record.subrecords {
+            subrecord_bytes.extend_from_slice(subrecord.subrecord_type.as_bytes());
+
+            if self.game_id == GameId::Morrowind {
+                subrecord_bytes.write_u32::<LittleEndian>(subrecord.data.len() as u32)?;
+            } else {
+                if subrecord.data.len() > u16::max_value() as usize {
+                    return Err(Error::DecodeError(Cow::from(
+                        "subrecord data is too large for a 2-byte size field",
+                    )));
+                }
+                subrecord_bytes.write_u16::<LittleEndian>(subrecord.data.len() as u16)?;
+            }
+
+            subrecord_bytes.extend_from_slice(&subrecord.data);
+        }
+
+        let mut output = Vec::with_capacity(header_len + subrecord_bytes.len() + self.data.body.len());
+        output.extend_from_slice(&fixed_prefix[0..4]);
+        output.write_u32::<LittleEndian>(subrecord_bytes.len() as u32)?;
+        output.extend_from_slice(&fixed_prefix[8..]);
+        output.extend_from_slice(&subrecord_bytes);
+        output.extend_from_slice(&self.data.body);
+
+        let mut file = File::create(path)?;
+        file.write_all(&output)?;
+
+        Ok(())
+    }
+}
+
+fn encode_subrecord(subrecord_type: &str, value: &str) -> Result<Subrecord, Error> {
+    let mut data = WINDOWS_1252
+        .encode(value, EncoderTrap::Strict)
+        .map_err(Error::DecodeError)?;
+    data.push(0);
+
+    Ok(Subrecord {
+        subrecord_type: subrecord_type.to_string(),
+        data: data,
+    })
 }
 
 fn masters(header_record: &Record) -> Result<Vec<String>, Error> {
@@ -235,12 +597,32 @@ fn masters(header_record: &Record) -> Result<Vec<String>, Error> {
         .collect::<Result<Vec<String>, Error>>()
 }
 
+/// Returns the FormIDs of records this plugin itself defines (as opposed to
+/// overriding from a master) whose object index falls outside the range a
+/// light master's compacted index can represent.
+fn invalid_light_master_form_ids(raw_form_ids: &[u32], masters_count: usize) -> Vec<u32> {
+    raw_form_ids
+        .iter()
+        .filter(|&&raw_form_id| {
+            let mod_index = (raw_form_id >> 24) as usize;
+            if mod_index < masters_count {
+                return false;
+            }
+
+            let object_index = raw_form_id & 0x00FF_FFFF;
+            object_index < LIGHT_MASTER_MIN_OBJECT_INDEX || object_index > LIGHT_MASTER_MAX_OBJECT_INDEX
+        })
+        .cloned()
+        .collect()
+}
+
 fn parse_form_ids<'a>(
     input: &'a [u8],
     game_id: GameId,
     filename: &str,
     header_record: &Record,
-) -> IResult<&'a [u8], Vec<FormId>> {
+    is_light: bool,
+) -> IResult<&'a [u8], (Vec<FormId>, Vec<u32>)> {
     let masters = match masters(header_record) {
         Ok(x) => x,
         Err(_) => return IResult::Error(ErrorKind::Custom(1)),
@@ -252,21 +634,30 @@ fn parse_form_ids<'a>(
 
         let form_ids: Vec<FormId> = record_form_ids
             .into_iter()
-            .map(|form_id| FormId::new(filename, &masters, form_id))
+            .map(|form_id| FormId::new(filename, &masters, form_id, false))
             .collect();
 
-        IResult::Done(input1, form_ids)
+        IResult::Done(input1, (form_ids, Vec::new()))
     } else {
         let (input1, groups) = try_parse!(input, many0!(apply!(Group::new, game_id)));
 
-        let mut form_ids: Vec<FormId> = Vec::new();
-        for group in groups {
-            form_ids.extend(group.form_ids.into_iter().map(|form_id| {
-                FormId::new(filename, &masters, form_id)
-            }));
+        let mut raw_form_ids: Vec<u32> = Vec::new();
+        for group in &groups {
+            raw_form_ids.extend(group.form_ids.iter().cloned());
         }
 
-        IResult::Done(input1, form_ids)
+        let invalid_light_form_ids = if is_light {
+            invalid_light_master_form_ids(&raw_form_ids, masters.len())
+        } else {
+            Vec::new()
+        };
+
+        let form_ids: Vec<FormId> = raw_form_ids
+            .into_iter()
+            .map(|form_id| FormId::new(filename, &masters, form_id, is_light))
+            .collect();
+
+        IResult::Done(input1, (form_ids, invalid_light_form_ids))
     }
 }
 
@@ -278,19 +669,29 @@ fn parse_plugin<'a>(
 ) -> IResult<&'a [u8], PluginData> {
     let (input1, header_record) = try_parse!(input, apply!(Record::parse, game_id, false));
 
+    let header_bytes = input[0..(input.len() - input1.len())].to_vec();
+
     if load_header_only {
         return IResult::Done(
             input1,
             PluginData {
                 header_record: header_record,
                 form_ids: Vec::new(),
+                invalid_light_form_ids: Vec::new(),
+                header_bytes: header_bytes,
+                body: Vec::new(),
+                header_only: true,
             },
         );
     }
 
-    let (input2, form_ids) = try_parse!(
+    let body = input1.to_vec();
+
+    let is_light = is_light_flagged(game_id, header_record.header.flags, filename);
+
+    let (input2, (form_ids, invalid_light_form_ids)) = try_parse!(
         input1,
-        apply!(parse_form_ids, game_id, filename, &header_record)
+        apply!(parse_form_ids, game_id, filename, &header_record, is_light)
     );
 
     IResult::Done(
@@ -298,6 +699,306 @@ fn parse_plugin<'a>(
         PluginData {
             header_record: header_record,
             form_ids: form_ids,
+            invalid_light_form_ids: invalid_light_form_ids,
+            header_bytes: header_bytes,
+            body: body,
+            header_only: false,
         },
     )
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use subrecord::Subrecord;
+
+    use super::*;
+
+    fn header_bytes_stub() -> Vec<u8> {
+        vec![
+            b'T', b'E', b'S', b'4', // type
+            0, 0, 0, 0, // data size, patched by write_file
+            0, 0, 0, 0, // flags
+            0, 0, 0, 0, // form ID
+            0, 0, 0, 0, // version control info
+            0, 0, 0, 0, // internal version
+        ]
+    }
+
+    fn header_bytes_stub_tes3() -> Vec<u8> {
+        vec![
+            b'T', b'E', b'S', b'3', // type
+            0, 0, 0, 0, // data size, patched by write_file
+            0, 0, 0, 0, // unknown
+            0, 0, 0, 0, // flags
+        ]
+    }
+
+    #[test]
+    fn set_masters_replaces_existing_mast_subrecords() {
+        let mut plugin = Plugin::new(GameId::Skyrim, Path::new("Test.esp"));
+        plugin.data.header_record.subrecords = vec![
+            Subrecord {
+                subrecord_type: "MAST".to_string(),
+                data: b"Old.esm\0".to_vec(),
+            },
+        ];
+
+        plugin
+            .set_masters(&["New.esm".to_string(), "Other.esm".to_string()])
+            .unwrap();
+
+        assert_eq!(
+            plugin.masters().unwrap(),
+            vec!["New.esm".to_string(), "Other.esm".to_string()]
+        );
+    }
+
+    #[test]
+    fn write_file_round_trips_header_and_body() {
+        let mut plugin = Plugin::new(GameId::Skyrim, Path::new("Test.esp"));
+        plugin.data.header_record.subrecords = vec![
+            Subrecord {
+                subrecord_type: "SNAM".to_string(),
+                data: b"Old description\0".to_vec(),
+            },
+        ];
+        plugin.data.header_bytes = header_bytes_stub();
+        plugin.data.body = b"body-bytes".to_vec();
+        plugin.data.header_only = false;
+
+        plugin.set_description("New description").unwrap();
+
+        let path = env::temp_dir().join("libespm_write_file_round_trip_test.esp");
+        plugin.write_file(&path).unwrap();
+
+        let written = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(&written[0..4], b"TES4");
+        assert!(written.ends_with(b"body-bytes"));
+
+        let mut roundtripped = Plugin::new(GameId::Skyrim, Path::new("Test.esp"));
+        roundtripped.parse(&written, false).unwrap();
+        assert_eq!(
+            roundtripped.description().unwrap(),
+            Some("New description".to_string())
+        );
+    }
+
+    #[test]
+    fn write_file_writes_four_byte_subrecord_sizes_for_morrowind() {
+        let mut plugin = Plugin::new(GameId::Morrowind, Path::new("Test.esm"));
+        plugin.data.header_record.subrecords = vec![
+            Subrecord {
+                subrecord_type: "HEDR".to_string(),
+                data: vec![0u8; 300],
+            },
+        ];
+        plugin.data.header_bytes = header_bytes_stub_tes3();
+        plugin.data.body = b"body-bytes".to_vec();
+        plugin.data.header_only = false;
+
+        let path = env::temp_dir().join("libespm_write_file_morrowind_test.esm");
+        plugin.write_file(&path).unwrap();
+
+        let written = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(&written[0..4], b"TES3");
+
+        let subrecord_size_offset = TES3_RECORD_HEADER_SIZE + 4;
+        let size = Cursor::new(&written[subrecord_size_offset..subrecord_size_offset + 4])
+            .read_u32::<LittleEndian>()
+            .unwrap();
+        assert_eq!(size, 300);
+        assert!(written.ends_with(b"body-bytes"));
+    }
+
+    #[test]
+    fn write_file_rejects_oversized_subrecord_for_non_morrowind() {
+        let mut plugin = Plugin::new(GameId::Skyrim, Path::new("Test.esp"));
+        plugin.data.header_record.subrecords = vec![
+            Subrecord {
+                subrecord_type: "XXXX".to_string(),
+                data: vec![0u8; u16::max_value() as usize + 1],
+            },
+        ];
+        plugin.data.header_bytes = header_bytes_stub();
+        plugin.data.header_only = false;
+
+        let path = env::temp_dir().join("libespm_write_file_oversized_subrecord_test.esp");
+
+        match plugin.write_file(&path) {
+            Err(Error::DecodeError(_)) => {}
+            other => panic!("expected Err(DecodeError), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn write_file_rejects_header_only_parse() {
+        let mut plugin = Plugin::new(GameId::Skyrim, Path::new("Test.esp"));
+        plugin.data.header_only = true;
+
+        let path = env::temp_dir().join("libespm_write_file_header_only_test.esp");
+
+        match plugin.write_file(&path) {
+            Err(Error::PluginNotFullyParsed) => {}
+            other => panic!("expected Err(PluginNotFullyParsed), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_file_header_rejects_a_data_size_larger_than_the_file() {
+        let path = env::temp_dir().join("libespm_parse_file_header_oversized_test.esp");
+
+        let mut record_bytes = header_bytes_stub();
+        // Claim a data size far larger than any bytes that follow the header.
+        Cursor::new(&mut record_bytes[4..8])
+            .write_u32::<LittleEndian>(1_000_000)
+            .unwrap();
+        fs::write(&path, &record_bytes).unwrap();
+
+        let mut plugin = Plugin::new(GameId::Skyrim, &path);
+        let result = plugin.parse_file_header();
+
+        let _ = fs::remove_file(&path);
+
+        match result {
+            Err(Error::ParsingError) => {}
+            other => panic!("expected Err(ParsingError), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn write_cache_then_load_cache_round_trips_parsed_data() {
+        let path = env::temp_dir().join("libespm_cache_round_trip_test.esp");
+        fs::write(&path, b"plugin-bytes").unwrap();
+        let cache_path = env::temp_dir().join("libespm_cache_round_trip_test.cache");
+
+        let mut plugin = Plugin::new(GameId::Skyrim, &path);
+        plugin.data.header_record.subrecords = vec![
+            Subrecord {
+                subrecord_type: "SNAM".to_string(),
+                data: b"desc\0".to_vec(),
+            },
+        ];
+        plugin.data.header_only = false;
+
+        plugin.write_cache(&cache_path).unwrap();
+
+        let mut loaded = Plugin::new(GameId::Skyrim, &path);
+        loaded.load_cache(&cache_path).unwrap();
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&cache_path);
+
+        assert_eq!(loaded.description().unwrap(), Some("desc".to_string()));
+    }
+
+    #[test]
+    fn parse_file_use_cache_reuses_a_fresh_entry_that_satisfies_the_request() {
+        let path = env::temp_dir().join("libespm_cache_reuse_test.esp");
+        fs::write(&path, b"not a valid plugin").unwrap();
+        let cache_path = env::temp_dir().join("libespm_cache_reuse_test.cache");
+
+        let metadata = path.metadata().unwrap();
+        let mut cached_data = PluginData::default();
+        cached_data.header_only = false;
+        cached_data.header_record.subrecords = vec![
+            Subrecord {
+                subrecord_type: "SNAM".to_string(),
+                data: b"cached\0".to_vec(),
+            },
+        ];
+
+        let entry = CacheEntry {
+            file_size: metadata.len(),
+            modified: metadata.modified().unwrap(),
+            data: cached_data,
+        };
+        let mut file = File::create(&cache_path).unwrap();
+        file.write_all(&bincode::serialize(&entry).unwrap()).unwrap();
+
+        let mut plugin = Plugin::new(GameId::Skyrim, &path);
+        plugin.parse_file_use_cache(true, &cache_path).unwrap();
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&cache_path);
+
+        assert_eq!(plugin.description().unwrap(), Some("cached".to_string()));
+    }
+
+    #[test]
+    fn parse_file_use_cache_does_not_reuse_a_header_only_entry_for_a_full_parse_request() {
+        let path = env::temp_dir().join("libespm_cache_stale_test.esp");
+        fs::write(&path, b"not a valid plugin").unwrap();
+        let cache_path = env::temp_dir().join("libespm_cache_stale_test.cache");
+
+        let metadata = path.metadata().unwrap();
+        let entry = CacheEntry {
+            file_size: metadata.len(),
+            modified: metadata.modified().unwrap(),
+            data: PluginData {
+                header_only: true,
+                ..PluginData::default()
+            },
+        };
+        let mut file = File::create(&cache_path).unwrap();
+        file.write_all(&bincode::serialize(&entry).unwrap()).unwrap();
+
+        let mut plugin = Plugin::new(GameId::Skyrim, &path);
+        // A header-only entry can't satisfy a full-parse request, so this
+        // must fall back to re-parsing the (invalid) file content instead of
+        // silently returning the stale header-only data.
+        let result = plugin.parse_file_use_cache(false, &cache_path);
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&cache_path);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn form_id_new_masks_light_plugins_own_object_index_to_12_bits() {
+        let masters = vec!["Skyrim.esm".to_string()];
+        // Mod index 1 equals masters.len(), so this is a record the plugin
+        // itself defines rather than an override of Skyrim.esm.
+        let raw_form_id = 0x0100_1234;
+
+        let form_id = FormId::new("Plugin.esl", &masters, raw_form_id, true);
+
+        assert_eq!(form_id.object_index(), 0x234);
+        assert_eq!(form_id.plugin_name(), "Plugin.esl");
+    }
+
+    #[test]
+    fn form_id_new_keeps_full_object_index_for_non_light_plugins() {
+        let masters = vec!["Skyrim.esm".to_string()];
+        let raw_form_id = 0x0100_1234;
+
+        let form_id = FormId::new("Plugin.esp", &masters, raw_form_id, false);
+
+        assert_eq!(form_id.object_index(), 0x00_1234);
+    }
+
+    #[test]
+    fn invalid_light_master_form_ids_flags_only_out_of_range_self_records() {
+        let raw_form_ids = vec![
+            0x0100_0900, // self-authored, in range
+            0x0100_2000, // self-authored, out of range
+            0x0000_0500, // override of masters[0], not self-authored
+        ];
+
+        let invalid = invalid_light_master_form_ids(&raw_form_ids, 1);
+
+        assert_eq!(invalid, vec![0x0100_2000]);
+    }
+
+    #[test]
+    fn is_light_flagged_recognises_ghosted_esl_files() {
+        assert!(is_light_flagged(GameId::Skyrim, 0, "Plugin.esl.ghost"));
+        assert!(!is_light_flagged(GameId::Skyrim, 0, "Plugin.esp.ghost"));
+    }
+}
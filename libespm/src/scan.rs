@@ -0,0 +1,244 @@
+/*
+ * This file is part of libespm
+ *
+ * Copyright (C) 2017 Oliver Hamlet
+ *
+ * libespm is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * libespm is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with libespm. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use game_id::GameId;
+use plugin::{Error, Plugin};
+
+/// Number of worker threads used to parse plugins found by `scan_dir`.
+const WORKER_COUNT: usize = 4;
+
+fn is_plugin_extension(extension: &str) -> bool {
+    let extension = extension.to_lowercase();
+    extension == "esp" || extension == "esm" || extension == "esl"
+}
+
+/// Returns the path's extension as used to decide whether it is a plugin,
+/// resolving `.ghost`-suffixed files to the extension they are hiding.
+fn plugin_extension(path: &Path) -> Option<String> {
+    let extension = path.extension().and_then(|e| e.to_str())?;
+
+    if extension.eq_ignore_ascii_case("ghost") {
+        path.file_stem()
+            .and_then(|stem| Path::new(stem).extension())
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_string())
+    } else {
+        Some(extension.to_string())
+    }
+}
+
+/// Recursively walks `dir`, appending the path of every file that looks like
+/// a plugin to `paths`. Symlinked directories are not followed, so cycles
+/// created by symlink loops cannot cause infinite recursion.
+///
+/// Only a failure to read `dir` itself is returned as an `Err`: a problem
+/// with one entry (a non-UTF-8 filename, a permission error) is recorded in
+/// `errors` against that entry's path and the walk continues, since one
+/// oddly-named or unreadable file should not stop the rest of the directory
+/// tree from being scanned.
+fn find_plugin_paths(
+    dir: &Path,
+    paths: &mut Vec<PathBuf>,
+    errors: &mut Vec<(PathBuf, Error)>,
+) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                errors.push((dir.to_path_buf(), Error::from(error)));
+                continue;
+            }
+        };
+        let path = entry.path();
+
+        if entry.file_name().to_str().is_none() {
+            errors.push((path, Error::NonUtf8FilePath));
+            continue;
+        }
+
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(error) => {
+                errors.push((path, Error::from(error)));
+                continue;
+            }
+        };
+
+        if file_type.is_dir() {
+            if let Err(error) = find_plugin_paths(&path, paths, errors) {
+                errors.push((path, error));
+            }
+        } else if file_type.is_file() {
+            if plugin_extension(&path).map_or(false, |e| is_plugin_extension(&e)) {
+                paths.push(path);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Recursively enumerates the plugins in `data_path` and parses them
+/// concurrently across a small pool of worker threads. Each plugin is parsed
+/// with `Plugin::parse_file_header` when `load_header_only` is `true`, and
+/// fully with `Plugin::parse_file` otherwise; there is no default. Files that fail to parse do not abort the scan: their error is
+/// recorded against their path instead.
+pub fn scan_dir(
+    data_path: &Path,
+    game_id: GameId,
+    load_header_only: bool,
+) -> HashMap<PathBuf, Result<Plugin, Error>> {
+    let mut paths = Vec::new();
+    let mut walk_errors = Vec::new();
+
+    if let Err(error) = find_plugin_paths(data_path, &mut paths, &mut walk_errors) {
+        let mut results = HashMap::new();
+        results.insert(data_path.to_path_buf(), Err(error));
+        return results;
+    }
+
+    let (path_tx, path_rx) = mpsc::channel::<PathBuf>();
+    let path_rx = Arc::new(Mutex::new(path_rx));
+    let (result_tx, result_rx) = mpsc::channel::<(PathBuf, Result<Plugin, Error>)>();
+
+    let paths_len = paths.len();
+    for path in paths {
+        path_tx.send(path).expect("receiver should not be dropped");
+    }
+    drop(path_tx);
+
+    let mut workers = Vec::with_capacity(WORKER_COUNT);
+    for _ in 0..WORKER_COUNT {
+        let path_rx = Arc::clone(&path_rx);
+        let result_tx = result_tx.clone();
+
+        workers.push(thread::spawn(move || loop {
+            let path = {
+                let path_rx = path_rx.lock().expect("mutex should not be poisoned");
+                path_rx.recv()
+            };
+
+            let path = match path {
+                Ok(path) => path,
+                Err(_) => break,
+            };
+
+            let mut plugin = Plugin::new(game_id, &path);
+            let result = if load_header_only {
+                plugin.parse_file_header()
+            } else {
+                plugin.parse_file(false)
+            };
+            let result = result.map(|_| plugin);
+
+            result_tx
+                .send((path, result))
+                .expect("receiver should not be dropped");
+        }));
+    }
+    drop(result_tx);
+
+    let mut results = HashMap::with_capacity(paths_len + walk_errors.len());
+    for (path, result) in result_rx {
+        results.insert(path, result);
+    }
+    for (path, error) in walk_errors {
+        results.insert(path, Err(error));
+    }
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use game_id::GameId;
+
+    use super::*;
+
+    fn unique_temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("libespm_scan_test_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn scan_dir_finds_plugins_including_ghosted_ones_recursively() {
+        let root = unique_temp_dir("finds_plugins");
+        fs::create_dir_all(root.join("sub")).unwrap();
+
+        fs::write(root.join("Plugin.esp"), b"").unwrap();
+        fs::write(root.join("sub").join("Master.esm.ghost"), b"").unwrap();
+        fs::write(root.join("readme.txt"), b"").unwrap();
+
+        let results = scan_dir(&root, GameId::Skyrim, true);
+
+        let _ = fs::remove_dir_all(&root);
+
+        assert!(results.contains_key(&root.join("Plugin.esp")));
+        assert!(results.contains_key(&root.join("sub").join("Master.esm.ghost")));
+        assert!(!results.contains_key(&root.join("readme.txt")));
+    }
+
+    #[test]
+    fn scan_dir_does_not_abort_when_a_plugin_fails_to_parse() {
+        let root = unique_temp_dir("bad_plugin");
+
+        fs::write(root.join("NotAPlugin.esp"), b"this is not a valid plugin").unwrap();
+
+        let results = scan_dir(&root, GameId::Skyrim, true);
+
+        let _ = fs::remove_dir_all(&root);
+
+        let result = results
+            .get(&root.join("NotAPlugin.esp"))
+            .expect("the malformed plugin should still be reported");
+        assert!(result.is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scan_dir_does_not_follow_symlinked_directories() {
+        use std::os::unix::fs::symlink;
+
+        let root = unique_temp_dir("symlink_loop");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        symlink(&root, root.join("sub").join("loop")).unwrap();
+
+        let results = scan_dir(&root, GameId::Skyrim, true);
+
+        let _ = fs::remove_dir_all(&root);
+
+        assert!(results.is_empty());
+    }
+}